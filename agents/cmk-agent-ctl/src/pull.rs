@@ -3,58 +3,189 @@
 // conditions defined in the file COPYING, which is part of this source code package.
 
 use core::future::Future;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::io::Result as IoResult;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use super::{config, constants, monitoring_data, tls_server, types};
 use anyhow::{anyhow, Context, Result as AnyhowResult};
 use log::{info, warn};
-use std::net::{IpAddr, SocketAddr};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Semaphore;
-use tokio::time::{timeout, Duration};
+use tokio::time::{sleep, timeout, Duration};
+use trust_dns_resolver::TokioAsyncResolver;
 use tokio_rustls::TlsAcceptor;
 
 const TLS_ID: &[u8] = b"16";
 const HEADER_VERSION: &[u8] = b"\x00\x00";
 
+// Authenticated controller, derived from its certificate CN (the registration UUID).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControllerIdentity(String);
+
+impl std::fmt::Display for ControllerIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Number of per-IP semaphore slots; at this size cold idle entries are evicted to
+// make room. The ring only grows past it while every slot has an active connection.
+const DEFAULT_GUARD_CAPACITY: usize = 8192;
+
+// CLOCK-Pro residency state of a per-IP slot.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Hot,
+    Cold,
+    Test,
+}
+
+struct Slot {
+    ip: IpAddr,
+    sem: Arc<Semaphore>,
+    state: SlotState,
+    referenced: bool,
+}
+
 pub struct MaxConnectionsGuard {
     max_connections: usize,
-    active_connections: HashMap<IpAddr, Arc<Semaphore>>,
+    capacity: usize,
+    // Maps an IP to its slot index so lookups stay O(1) while eviction walks the ring.
+    index: HashMap<IpAddr, usize>,
+    slots: Vec<Slot>,
+    // CLOCK hand: the next slot the eviction sweep will inspect.
+    hand: usize,
+}
+
+// TTL for cached hostname resolutions before they are re-resolved.
+const DEFAULT_ALLOWLIST_TTL: u64 = 120;
+
+// Source IP allowlist: literal IPs, CIDR networks, or DNS hostnames.
+// Hostnames are resolved in the background and cached with a TTL.
+pub struct AllowList {
+    entries: Vec<String>,
+    resolver: Option<TokioAsyncResolver>,
+    // Resolved hostname addresses, shared with the background refresher.
+    resolved: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    ttl: Duration,
+    refresher_started: bool,
 }
 
-fn is_addr_allowed(addr: &SocketAddr, allowed_ip: &[String]) -> bool {
-    if allowed_ip.is_empty() {
-        return true;
+impl AllowList {
+    pub fn new(entries: Vec<String>) -> Self {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().ok();
+        AllowList {
+            entries,
+            resolver,
+            resolved: Arc::new(Mutex::new(HashMap::new())),
+            ttl: Duration::from_secs(DEFAULT_ALLOWLIST_TTL),
+            refresher_started: false,
+        }
     }
-    for ip in allowed_ip {
-        // Our list may contain both network, ip addresses and bad data(!)
-        // Examples: network - 192.168.1.14/24, address - 127.0.0.1
-        if let Ok(allowed_net) = ip.parse::<ipnet::IpNet>() {
-            if allowed_net.contains(&addr.ip()) {
-                return true;
+
+    // Start a background task that re-resolves hostname entries on a TTL and writes
+    // them into the shared cache. Resolution stays off the accept path so a slow or
+    // unreachable resolver never stalls acceptance of new pull connections. Literal
+    // IP/CIDR entries need no resolution; a non-IP/CIDR/hostname entry is warned
+    // about once, and a transient lookup failure keeps the last-known-good addresses.
+    fn refresh(&mut self) {
+        if self.refresher_started {
+            return;
+        }
+        let resolver = match &self.resolver {
+            Some(resolver) => resolver.clone(),
+            None => return,
+        };
+        let hostnames: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                entry.parse::<ipnet::IpNet>().is_err() && entry.parse::<IpAddr>().is_err()
+            })
+            .cloned()
+            .collect();
+        if hostnames.is_empty() {
+            return;
+        }
+        self.refresher_started = true;
+        let resolved = self.resolved.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            let mut ever_resolved: HashSet<String> = HashSet::new();
+            let mut warned: HashSet<String> = HashSet::new();
+            loop {
+                for host in &hostnames {
+                    match resolver.lookup_ip(host.as_str()).await {
+                        Ok(lookup) => {
+                            let addrs: Vec<IpAddr> = lookup.iter().collect();
+                            ever_resolved.insert(host.clone());
+                            resolved.lock().unwrap().insert(host.clone(), addrs);
+                        }
+                        // A name that resolved before but failed now: keep the
+                        // last-known-good addresses and stay quiet, it is valid.
+                        Err(_) if ever_resolved.contains(host) => {}
+                        // A name that never resolved is not a valid entry; warn once.
+                        Err(_) => {
+                            if warned.insert(host.clone()) {
+                                warn!(
+                                    "PULL: allowlist entry '{}' is not a valid IP, network, or resolvable hostname",
+                                    host
+                                );
+                            }
+                        }
+                    }
+                }
+                sleep(ttl).await;
             }
+        });
+    }
+
+    fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        if self.entries.is_empty() {
+            return true;
         }
-        if let Ok(allowed_addr) = ip.parse::<IpAddr>() {
-            if allowed_addr == addr.ip() {
-                return true;
+        for entry in &self.entries {
+            // Our list may contain networks, ip addresses, hostnames and bad data(!)
+            // Examples: network - 192.168.1.14/24, address - 127.0.0.1, host - mon.example.com
+            if let Ok(allowed_net) = entry.parse::<ipnet::IpNet>() {
+                if allowed_net.contains(&addr.ip()) {
+                    return true;
+                }
+            }
+            if let Ok(allowed_addr) = entry.parse::<IpAddr>() {
+                if allowed_addr == addr.ip() {
+                    return true;
+                }
+            }
+            if let Ok(resolved) = self.resolved.lock() {
+                if let Some(addrs) = resolved.get(entry) {
+                    if addrs.contains(&addr.ip()) {
+                        return true;
+                    }
+                }
             }
         }
-        // NOTE: no reporting about bad data here.
-        // We prefer to ignore error here: despite the possibility
-        // to have invalid settings we should check and report this once
+        false
     }
-    false
 }
 
 impl MaxConnectionsGuard {
     pub fn new(max_connections: usize) -> Self {
+        Self::with_capacity(max_connections, DEFAULT_GUARD_CAPACITY)
+    }
+
+    pub fn with_capacity(max_connections: usize, capacity: usize) -> Self {
         MaxConnectionsGuard {
             max_connections,
-            active_connections: HashMap::new(),
+            capacity: capacity.max(1),
+            index: HashMap::new(),
+            slots: Vec::new(),
+            hand: 0,
         }
     }
 
@@ -63,12 +194,8 @@ impl MaxConnectionsGuard {
         addr: SocketAddr,
         fut: impl Future<Output = AnyhowResult<()>>,
     ) -> AnyhowResult<impl Future<Output = AnyhowResult<()>>> {
-        let ip_addr = addr.ip();
-        let sem = self
-            .active_connections
-            .entry(ip_addr)
-            .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections)));
-        if let Ok(permit) = sem.clone().try_acquire_owned() {
+        let sem = self.slot_for(addr.ip());
+        if let Ok(permit) = sem.try_acquire_owned() {
             Ok(async move {
                 let res = fut.await;
                 drop(permit);
@@ -78,6 +205,191 @@ impl MaxConnectionsGuard {
             Err(anyhow!("Too many active connections"))
         }
     }
+
+    // Semaphore for `ip`, creating or reclaiming a slot; reuse sets the reference bit.
+    fn slot_for(&mut self, ip: IpAddr) -> Arc<Semaphore> {
+        if let Some(&i) = self.index.get(&ip) {
+            let slot = &mut self.slots[i];
+            slot.referenced = true;
+            if slot.state == SlotState::Test {
+                slot.state = SlotState::Hot;
+            }
+            return slot.sem.clone();
+        }
+
+        let new_slot = Slot {
+            ip,
+            sem: Arc::new(Semaphore::new(self.max_connections)),
+            state: SlotState::Test,
+            referenced: false,
+        };
+        let sem = new_slot.sem.clone();
+
+        if self.slots.len() < self.capacity {
+            let i = self.slots.len();
+            self.slots.push(new_slot);
+            self.index.insert(ip, i);
+        } else if let Some(i) = self.try_evict() {
+            self.index.remove(&self.slots[i].ip);
+            self.slots[i] = new_slot;
+            self.index.insert(ip, i);
+        } else {
+            // Every resident slot still has an active connection, so none can be
+            // dropped without breaking its per-IP guarantee. Admit this IP on an
+            // extra slot past capacity; the ring does not shrink back, but this slot
+            // can be evicted like any other once it is idle.
+            let i = self.slots.len();
+            self.slots.push(new_slot);
+            self.index.insert(ip, i);
+        }
+        sem
+    }
+
+    // Advance the CLOCK hand for a cold, idle slot to reclaim. Busy slots (permits
+    // held) are never evicted; referenced/hot slots get a second chance.
+    fn try_evict(&mut self) -> Option<usize> {
+        let n = self.slots.len();
+        if n == 0 {
+            return None;
+        }
+        for _ in 0..(2 * n) {
+            let i = self.hand;
+            self.hand = (self.hand + 1) % n;
+
+            let is_idle =
+                self.slots[i].sem.available_permits() == self.max_connections;
+            if !is_idle {
+                continue;
+            }
+
+            let slot = &mut self.slots[i];
+            if slot.referenced {
+                slot.referenced = false;
+                if slot.state == SlotState::Hot {
+                    slot.state = SlotState::Cold;
+                }
+                continue;
+            }
+            match slot.state {
+                SlotState::Hot => slot.state = SlotState::Cold,
+                SlotState::Cold | SlotState::Test => return Some(i),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "quic")]
+pub fn quic_pull(
+    registry: config::Registry,
+    port: types::Port,
+    max_connections: usize,
+    allowed_ip: Vec<String>,
+    socket_config: PullSocketConfig,
+    compression: CompressionAlgorithm,
+) -> AnyhowResult<()> {
+    let guard = MaxConnectionsGuard::new(max_connections);
+    let port: u16 = format!("{}", port).parse().context("Invalid pull port")?;
+    let bind = SocketAddr::new(socket_config.bind_address, port);
+    _quic_pull(
+        registry,
+        guard,
+        collect_and_encode_mondata,
+        compression,
+        bind,
+        AllowList::new(allowed_ip),
+    )
+}
+
+// Keepalive probes free a guard permit when a controller vanishes mid-transfer.
+#[derive(Clone)]
+pub struct TcpKeepaliveConfig {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+// Bind address and socket tuning for the pull listener; defaults to dual-stack IPv6.
+#[derive(Clone)]
+pub struct PullSocketConfig {
+    pub bind_address: IpAddr,
+    pub reuse_address: bool,
+    pub nodelay: bool,
+    pub keepalive: Option<TcpKeepaliveConfig>,
+}
+
+impl Default for PullSocketConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            reuse_address: true,
+            nodelay: true,
+            keepalive: Some(TcpKeepaliveConfig {
+                idle: Duration::from_secs(120),
+                interval: Duration::from_secs(30),
+                retries: 4,
+            }),
+        }
+    }
+}
+
+impl PullSocketConfig {
+    fn keepalive(&self) -> Option<TcpKeepalive> {
+        self.keepalive.as_ref().map(|ka| {
+            TcpKeepalive::new()
+                .with_time(ka.idle)
+                .with_interval(ka.interval)
+                .with_retries(ka.retries)
+        })
+    }
+
+    // Apply TCP_NODELAY and keepalive to an accepted stream.
+    fn apply_to_stream(&self, stream: &TcpStream) -> AnyhowResult<()> {
+        stream
+            .set_nodelay(self.nodelay)
+            .context("Failed setting TCP_NODELAY on pull connection")?;
+        if let Some(ka) = self.keepalive() {
+            SockRef::from(stream)
+                .set_tcp_keepalive(&ka)
+                .context("Failed setting keepalive on pull connection")?;
+        }
+        Ok(())
+    }
+}
+
+fn make_listener(bind: SocketAddr, config: &PullSocketConfig) -> AnyhowResult<TcpListener> {
+    let domain = match bind.ip() {
+        IpAddr::V4(_) => Domain::IPV4,
+        IpAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+        .context("Failed creating pull listener socket")?;
+    if config.reuse_address {
+        socket
+            .set_reuse_address(true)
+            .context("Failed setting SO_REUSEADDR")?;
+    }
+    // Dual-stack: an unspecified IPv6 bind should also accept IPv4 controllers.
+    if domain == Domain::IPV6 && bind.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket
+            .set_only_v6(false)
+            .context("Failed enabling dual-stack on pull listener")?;
+    }
+    if let Some(ka) = config.keepalive() {
+        socket
+            .set_tcp_keepalive(&ka)
+            .context("Failed setting keepalive on pull listener")?;
+    }
+    socket
+        .set_nonblocking(true)
+        .context("Failed setting pull listener non-blocking")?;
+    socket
+        .bind(&bind.into())
+        .with_context(|| format!("Failed binding pull listener to {}", bind))?;
+    socket
+        .listen(1024)
+        .context("Failed listening on pull socket")?;
+    TcpListener::from_std(socket.into()).context("Failed converting pull listener")
 }
 
 pub fn pull(
@@ -86,22 +398,28 @@ pub fn pull(
     port: types::Port,
     max_connections: usize,
     allowed_ip: Vec<String>,
+    socket_config: PullSocketConfig,
+    compression: CompressionAlgorithm,
 ) -> AnyhowResult<()> {
-    let pull_config = PullConfigurationImpl::new(registry, legacy_pull_marker)?;
+    let pull_config = PullConfigurationImpl::new(registry, legacy_pull_marker, compression)?;
     let guard = MaxConnectionsGuard::new(max_connections);
     // Plain agent output for legacy handling only
     let collect_plain_mondata = monitoring_data::async_collect;
     // Compressed monitoring data with internal protocol handler
     let collect_encoded_mondata = collect_and_encode_mondata;
-    let addr = format!("0.0.0.0:{}", port);
+    let port: u16 = format!("{}", port)
+        .parse()
+        .context("Invalid pull port")?;
+    let bind = SocketAddr::new(socket_config.bind_address, port);
     _pull(
         pull_config,
         guard,
         collect_plain_mondata,
         collect_encoded_mondata,
-        &addr,
+        bind,
+        socket_config,
         constants::CONNECTION_TIMEOUT,
-        &allowed_ip,
+        AllowList::new(allowed_ip),
     )
 }
 
@@ -110,10 +428,11 @@ pub async fn _pull<Fut1, Fut2>(
     mut pull_config: impl PullConfiguration,
     mut guard: MaxConnectionsGuard,
     collect_plain_mondata: impl Fn(std::net::IpAddr) -> Fut1,
-    collect_encoded_mondata: impl Fn(std::net::IpAddr) -> Fut2,
-    addr: &str,
+    collect_encoded_mondata: impl Fn(std::net::IpAddr, CompressionAlgorithm) -> Fut2 + Clone,
+    bind: SocketAddr,
+    socket_config: PullSocketConfig,
     timeout: u64,
-    allowed_ip: &[String],
+    mut allow_list: AllowList,
 ) -> AnyhowResult<()>
 where
     // TODO: Unify these two types. However, they must still be
@@ -121,7 +440,7 @@ where
     Fut1: Future<Output = IoResult<Vec<u8>>> + Send + 'static,
     Fut2: Future<Output = AnyhowResult<Vec<u8>>> + Send + 'static,
 {
-    let listener = TcpListener::bind(addr).await?;
+    let listener = make_listener(bind, &socket_config)?;
 
     loop {
         let (stream, remote) = listener
@@ -130,19 +449,27 @@ where
             .context("Failed accepting pull connection")?;
         info!("{}: Handling pull request", remote);
 
+        if let Err(err) = socket_config.apply_to_stream(&stream) {
+            warn!("PULL: Request from {} failed: {}", remote, err);
+            continue;
+        }
+
         pull_config.refresh()?;
-        if !is_addr_allowed(&remote, allowed_ip) {
+        allow_list.refresh();
+        if !allow_list.is_allowed(&remote) {
             warn!("PULL: Request from {} is not allowed", remote);
             continue;
         }
 
         let plain_mondata = collect_plain_mondata(remote.ip());
-        let encoded_mondata = collect_encoded_mondata(remote.ip());
 
         let request_handler_fut = handle_request(
             stream,
             plain_mondata,
-            encoded_mondata,
+            collect_encoded_mondata.clone(),
+            remote.ip(),
+            pull_config.pull_identities(),
+            pull_config.compression_algorithm(),
             pull_config.is_legacy_pull(),
             pull_config.tls_acceptor(),
             timeout,
@@ -163,9 +490,113 @@ where
     }
 }
 
+#[cfg(feature = "quic")]
+#[tokio::main(flavor = "current_thread")]
+pub async fn _quic_pull<Fut>(
+    mut registry: config::Registry,
+    mut guard: MaxConnectionsGuard,
+    collect_encoded_mondata: impl Fn(std::net::IpAddr, CompressionAlgorithm) -> Fut + Clone,
+    compression: CompressionAlgorithm,
+    bind: SocketAddr,
+    mut allow_list: AllowList,
+) -> AnyhowResult<()>
+where
+    Fut: Future<Output = AnyhowResult<Vec<u8>>> + Send + 'static,
+{
+    let server_config = tls_server::quic_server_config(registry.pull_connections())
+        .context("Could not initialize QUIC TLS.")?;
+    let endpoint =
+        quinn::Endpoint::server(server_config, bind).context("Failed binding QUIC endpoint")?;
+
+    while let Some(connecting) = endpoint.accept().await {
+        let remote = connecting.remote_address();
+        info!("{}: Handling pull request (QUIC)", remote);
+
+        // Re-read the registry per connection, exactly as the TCP path does, so a
+        // revoked/rotated controller is rejected live and newly-registered ones
+        // are admitted without restarting the process.
+        if registry.refresh()? {
+            let server_config = tls_server::quic_server_config(registry.pull_connections())
+                .context("Could not initialize QUIC TLS.")?;
+            endpoint.set_server_config(Some(server_config));
+        }
+        let pull_identities: Vec<ControllerIdentity> = registry
+            .pull_connections()
+            .map(|connection| ControllerIdentity(connection.uuid.to_string()))
+            .collect();
+
+        allow_list.refresh();
+        if !allow_list.is_allowed(&remote) {
+            warn!("PULL: Request from {} is not allowed", remote);
+            continue;
+        }
+
+        let connection_fut = handle_quic_request(
+            connecting,
+            collect_encoded_mondata.clone(),
+            remote.ip(),
+            pull_identities,
+            compression,
+        );
+
+        match guard.try_make_task_for_addr(remote, connection_fut) {
+            Ok(connection_fut) => {
+                tokio::spawn(async move {
+                    if let Err(err) = connection_fut.await {
+                        warn!("PULL: Request from {} failed: {}", remote, err)
+                    };
+                });
+            }
+            Err(error) => {
+                warn!("PULL: Request from {} failed: {}", remote, error);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "quic")]
+async fn handle_quic_request<Fut>(
+    connecting: quinn::Connecting,
+    collect_encoded_mondata: impl Fn(std::net::IpAddr, CompressionAlgorithm) -> Fut,
+    remote_ip: IpAddr,
+    pull_identities: Vec<ControllerIdentity>,
+    compression: CompressionAlgorithm,
+) -> AnyhowResult<()>
+where
+    Fut: Future<Output = AnyhowResult<Vec<u8>>>,
+{
+    // Collection is independent of the handshake, so run them concurrently.
+    let (mon_data, connection) =
+        tokio::join!(collect_encoded_mondata(remote_ip, compression), connecting);
+    let mon_data = mon_data?;
+    let connection = connection.context("QUIC handshake failed")?;
+
+    let identity = quic_peer_identity(&connection, &pull_identities)
+        .context("PULL: Rejecting handshake with unregistered client certificate")?;
+    info!("{} ({}): Authenticated pull request (QUIC)", remote_ip, identity);
+
+    let mut send = connection
+        .open_uni()
+        .await
+        .context("Failed opening QUIC stream")?;
+    send.write_all(&mon_data)
+        .await
+        .context("Failed writing monitoring data over QUIC")?;
+    send.finish().await.context("Failed finishing QUIC stream")?;
+    Ok(())
+}
+
 pub trait PullConfiguration {
     fn refresh(&mut self) -> AnyhowResult<()>;
     fn tls_acceptor(&self) -> TlsAcceptor;
+    fn pull_identities(&self) -> Vec<ControllerIdentity>;
+    // The pull protocol is server-writes-only: the agent just writes the
+    // compression-header byte plus payload and the controller reads it back, with
+    // no request in which a controller could advertise accepted algorithms. So
+    // compression is a single server-configured choice for the listener, not
+    // negotiated per connection; the header byte still lets the server decode it.
+    fn compression_algorithm(&self) -> CompressionAlgorithm;
     fn is_legacy_pull(&self) -> bool;
 }
 struct PullConfigurationImpl {
@@ -173,12 +604,14 @@ struct PullConfigurationImpl {
     tls_acceptor: TlsAcceptor,
     registry: config::Registry,
     legacy_pull_marker: std::path::PathBuf,
+    compression: CompressionAlgorithm,
 }
 
 impl PullConfigurationImpl {
     pub fn new(
         registry: config::Registry,
         legacy_pull_marker: std::path::PathBuf,
+        compression: CompressionAlgorithm,
     ) -> AnyhowResult<Self> {
         Ok(PullConfigurationImpl {
             legacy_pull: is_legacy_pull(&registry, &legacy_pull_marker),
@@ -186,6 +619,7 @@ impl PullConfigurationImpl {
                 .context("Could not initialize TLS.")?,
             registry,
             legacy_pull_marker,
+            compression,
         })
     }
 }
@@ -204,6 +638,17 @@ impl PullConfiguration for PullConfigurationImpl {
         self.tls_acceptor.clone()
     }
 
+    fn pull_identities(&self) -> Vec<ControllerIdentity> {
+        self.registry
+            .pull_connections()
+            .map(|connection| ControllerIdentity(connection.uuid.to_string()))
+            .collect()
+    }
+
+    fn compression_algorithm(&self) -> CompressionAlgorithm {
+        self.compression
+    }
+
     fn is_legacy_pull(&self) -> bool {
         self.legacy_pull
     }
@@ -213,14 +658,20 @@ fn is_legacy_pull(registry: &config::Registry, legacy_pull_marker: &std::path::P
     legacy_pull_marker.exists() && registry.is_empty()
 }
 
-async fn handle_request(
+async fn handle_request<Fut>(
     mut stream: TcpStream,
     plain_mondata: impl Future<Output = IoResult<Vec<u8>>>,
-    encoded_modata: impl Future<Output = AnyhowResult<Vec<u8>>>,
+    collect_encoded_mondata: impl Fn(std::net::IpAddr, CompressionAlgorithm) -> Fut,
+    remote_ip: IpAddr,
+    pull_identities: Vec<ControllerIdentity>,
+    compression: CompressionAlgorithm,
     is_legacy_pull: bool,
     tls_acceptor: TlsAcceptor,
     timeout: u64,
-) -> AnyhowResult<()> {
+) -> AnyhowResult<()>
+where
+    Fut: Future<Output = AnyhowResult<Vec<u8>>>,
+{
     if is_legacy_pull {
         return handle_legacy_pull_request(stream, plain_mondata, timeout).await;
     }
@@ -234,18 +685,90 @@ async fn handle_request(
         timeout,
     );
 
-    let (mon_data, tls_stream) = tokio::join!(encoded_modata, handshake);
+    // Collection does not depend on the handshake, so run them concurrently and
+    // keep the per-pull latency at max(collect, handshake) rather than their sum.
+    let (mon_data, tls_stream) = tokio::join!(collect_encoded_mondata(remote_ip, compression), handshake);
     let mon_data = mon_data?;
     let mut tls_stream = tls_stream?;
 
-    with_timeout(
+    // The handshake verified the certificate chain; additionally require that the
+    // presented end-entity certificate still maps to a registered pull connection.
+    let identity = peer_identity(tls_stream.get_ref().1, &pull_identities)
+        .context("PULL: Rejecting handshake with unregistered client certificate")?;
+    info!("{} ({}): Authenticated pull request", remote_ip, identity);
+
+    let res = with_timeout(
         async move {
             tls_stream.write_all(&mon_data).await?;
             tls_stream.flush().await
         },
         timeout,
     )
-    .await
+    .await;
+    if let Err(ref err) = res {
+        warn!("{} ({}): Error serving pull request: {}", remote_ip, identity, err);
+    }
+    res
+}
+
+const NO_CLIENT_CERT: &str = "client did not present a certificate";
+
+// Check a derived identity against the registered pull connections.
+fn registered_identity(
+    end_entity: &rustls::Certificate,
+    pull_identities: &[ControllerIdentity],
+) -> AnyhowResult<ControllerIdentity> {
+    let identity = certificate_identity(end_entity)?;
+    if pull_identities.contains(&identity) {
+        Ok(identity)
+    } else {
+        Err(anyhow!(
+            "certificate identity {} is not a registered pull connection",
+            identity
+        ))
+    }
+}
+
+// Derive the controller identity from the peer's end-entity certificate.
+fn peer_identity(
+    connection: &rustls::ServerConnection,
+    pull_identities: &[ControllerIdentity],
+) -> AnyhowResult<ControllerIdentity> {
+    let end_entity = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| anyhow!(NO_CLIENT_CERT))?;
+    registered_identity(end_entity, pull_identities)
+}
+
+// Same as `peer_identity`, but for a QUIC connection's peer certificate.
+#[cfg(feature = "quic")]
+fn quic_peer_identity(
+    connection: &quinn::Connection,
+    pull_identities: &[ControllerIdentity],
+) -> AnyhowResult<ControllerIdentity> {
+    let certs = connection
+        .peer_identity()
+        .and_then(|any| any.downcast::<Vec<rustls::Certificate>>().ok())
+        .ok_or_else(|| anyhow!(NO_CLIENT_CERT))?;
+    let end_entity = certs.first().ok_or_else(|| anyhow!(NO_CLIENT_CERT))?;
+    registered_identity(end_entity, pull_identities)
+}
+
+// Extract the identity (subject CN) from a DER-encoded end-entity certificate.
+// A registered controller's certificate carries its registration UUID as the CN,
+// so this round-trips to `ControllerIdentity(connection.uuid)` (see the test).
+fn certificate_identity(cert: &rustls::Certificate) -> AnyhowResult<ControllerIdentity> {
+    use x509_parser::prelude::FromDer;
+    let (_, parsed) = x509_parser::certificate::X509Certificate::from_der(cert.as_ref())
+        .context("Failed parsing client certificate")?;
+    parsed
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| ControllerIdentity(cn.to_string()))
+        .ok_or_else(|| anyhow!("client certificate has no common name"))
 }
 
 async fn handle_legacy_pull_request(
@@ -275,19 +798,65 @@ pub fn disallow_legacy_pull(legacy_pull_marker: &std::path::Path) -> std::io::Re
     std::fs::remove_file(legacy_pull_marker)
 }
 
+// Compression for the pull payload; each algorithm has its own header byte.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    // Default algorithm, byte-compatible with existing Checkmk servers.
+    #[default]
+    Zlib,
+    Zstd,
+    Brotli,
+}
+
+impl CompressionAlgorithm {
+    // Header byte announced to the server for this algorithm.
+    fn header_byte(self) -> Vec<u8> {
+        match self {
+            CompressionAlgorithm::Zlib => monitoring_data::compression_header_info().pull,
+            CompressionAlgorithm::Zstd => b"\x02".to_vec(),
+            CompressionAlgorithm::Brotli => b"\x03".to_vec(),
+        }
+    }
+
+    fn compress(self, raw_agent_output: &[u8]) -> AnyhowResult<Vec<u8>> {
+        match self {
+            CompressionAlgorithm::Zlib => monitoring_data::compress(raw_agent_output),
+            CompressionAlgorithm::Zstd => {
+                zstd::stream::encode_all(raw_agent_output, 0).map_err(|err| anyhow!(err))
+            }
+            CompressionAlgorithm::Brotli => {
+                use std::io::Write;
+                let mut compressed = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+                writer.write_all(raw_agent_output)?;
+                writer.flush()?;
+                drop(writer);
+                Ok(compressed)
+            }
+        }
+    }
+}
+
 //TODO: Move this to monitoring_data.rs
-pub async fn collect_and_encode_mondata(remote_ip: std::net::IpAddr) -> AnyhowResult<Vec<u8>> {
+pub async fn collect_and_encode_mondata(
+    remote_ip: std::net::IpAddr,
+    compression: CompressionAlgorithm,
+) -> AnyhowResult<Vec<u8>> {
     let mon_data = monitoring_data::async_collect(remote_ip)
         .await
         .context("Error collecting monitoring data.")?;
-    encode_data_for_transport(&mon_data)
+    encode_data_for_transport(&mon_data, compression)
 }
 
-fn encode_data_for_transport(raw_agent_output: &[u8]) -> AnyhowResult<Vec<u8>> {
+fn encode_data_for_transport(
+    raw_agent_output: &[u8],
+    compression: CompressionAlgorithm,
+) -> AnyhowResult<Vec<u8>> {
     let mut encoded_data = HEADER_VERSION.to_vec();
-    encoded_data.append(&mut monitoring_data::compression_header_info().pull);
+    encoded_data.append(&mut compression.header_byte());
     encoded_data.append(
-        &mut monitoring_data::compress(raw_agent_output)
+        &mut compression
+            .compress(raw_agent_output)
             .context("Error compressing monitoring data")?,
     );
     Ok(encoded_data)
@@ -306,15 +875,110 @@ async fn with_timeout<T, E: 'static + Error + Send + Sync>(
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    // The rejection path assumes a registered controller presents its registration
+    // UUID as the certificate subject CN. Pin that invariant so a mismatch shows up
+    // here rather than as a total pull outage.
+    #[test]
+    fn test_certificate_identity_is_registration_uuid() {
+        let uuid = "b4e8f0d2-4c1a-4f3b-9a7e-000000000001";
+        let mut params = rcgen::CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, uuid);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = rustls::Certificate(cert.serialize_der().unwrap());
+        assert_eq!(
+            certificate_identity(&der).unwrap(),
+            ControllerIdentity(uuid.to_string())
+        );
+    }
+
     #[test]
     fn test_encode_data_for_transport() {
         let mut expected_result = b"\x00\x00\x01".to_vec();
         expected_result.append(&mut monitoring_data::compress(b"abc").unwrap());
-        assert_eq!(encode_data_for_transport(b"abc").unwrap(), expected_result);
+        assert_eq!(
+            encode_data_for_transport(b"abc", CompressionAlgorithm::Zlib).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_encode_data_for_transport_zstd() {
+        let mut expected_result = b"\x00\x00\x02".to_vec();
+        expected_result.append(&mut CompressionAlgorithm::Zstd.compress(b"abc").unwrap());
+        assert_eq!(
+            encode_data_for_transport(b"abc", CompressionAlgorithm::Zstd).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_encode_data_for_transport_brotli() {
+        let mut expected_result = b"\x00\x00\x03".to_vec();
+        expected_result.append(&mut CompressionAlgorithm::Brotli.compress(b"abc").unwrap());
+        assert_eq!(
+            encode_data_for_transport(b"abc", CompressionAlgorithm::Brotli).unwrap(),
+            expected_result
+        );
+    }
+
+    mod max_connections_guard {
+        use super::*;
+
+        fn ip(last: u8) -> IpAddr {
+            IpAddr::from([127, 0, 0, last])
+        }
+
+        #[test]
+        fn test_idle_cold_slots_are_evicted_at_capacity() {
+            let mut guard = MaxConnectionsGuard::with_capacity(1, 2);
+            // Fill both slots with idle (no permits held) entries ...
+            let _ = guard.slot_for(ip(1));
+            let _ = guard.slot_for(ip(2));
+            // ... a third IP must reclaim one of them rather than grow the ring.
+            let _ = guard.slot_for(ip(3));
+            assert_eq!(guard.slots.len(), 2);
+            assert!(guard.index.contains_key(&ip(3)));
+        }
+
+        #[test]
+        fn test_busy_slots_are_never_evicted() {
+            let mut guard = MaxConnectionsGuard::with_capacity(1, 2);
+            // Hold a permit for each slot so both have an active connection.
+            let busy_a = guard.slot_for(ip(1)).try_acquire_owned().unwrap();
+            let busy_b = guard.slot_for(ip(2)).try_acquire_owned().unwrap();
+            // No slot is evictable, so the new IP is admitted on a transient slot
+            // and the busy semaphores keep all their outstanding permits.
+            let _ = guard.slot_for(ip(3));
+            assert_eq!(guard.slots.len(), 3);
+            drop((busy_a, busy_b));
+        }
+
+        #[test]
+        fn test_reused_slot_survives_eviction_sweep() {
+            let mut guard = MaxConnectionsGuard::with_capacity(1, 2);
+            let _ = guard.slot_for(ip(1));
+            let _ = guard.slot_for(ip(2));
+            // Touch ip(1) again: it gets its reference bit and is promoted to hot.
+            let _ = guard.slot_for(ip(1));
+            let _ = guard.slot_for(ip(3));
+            assert!(guard.index.contains_key(&ip(1)));
+            assert!(!guard.index.contains_key(&ip(2)));
+        }
     }
 
     mod allowed_ip {
         use super::*;
+
+        // Literal IP/CIDR matching is independent of hostname resolution, so the
+        // tests exercise it through a freshly built allowlist.
+        fn is_addr_allowed(addr: &SocketAddr, allowed_ip: &[String]) -> bool {
+            AllowList::new(allowed_ip.to_vec()).is_allowed(addr)
+        }
+
         fn args_good() -> Vec<String> {
             vec![
                 "192.168.1.14/24".to_string(), // net